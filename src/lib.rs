@@ -2,7 +2,7 @@ use anyhow::{Result, bail};
 use mdbook::BookItem;
 use mdbook::book::{Book, Chapter};
 use mdbook::preprocess::{Preprocessor, PreprocessorContext};
-use regex::Regex;
+use pulldown_cmark::{CodeBlockKind, Event, Options, Parser, Tag, TagEnd};
 use sha2::{Sha256, Digest};
 use std::collections::HashMap;
 use std::num::NonZero;
@@ -32,6 +32,66 @@ struct ChapterEdit {
     cached_filename: String, // The cache filename that was used or created
 }
 
+/// Find all ```mermaid fenced code blocks in `content`, returning the diagram
+/// source (with any blockquote/list indentation already stripped by the
+/// parser) alongside the byte range of the whole block, from the opening
+/// fence to the closing fence.
+fn find_mermaid_blocks(content: &str) -> Vec<(String, Range<usize>)> {
+    let mut blocks = Vec::new();
+    let mut current: Option<(String, usize)> = None;
+
+    for (event, range) in Parser::new_ext(content, Options::all()).into_offset_iter() {
+        match event {
+            Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(info))) => {
+                if info.split_whitespace().next() == Some("mermaid") {
+                    current = Some((String::new(), range.start));
+                }
+            }
+            Event::Text(text) => {
+                if let Some((code, _)) = current.as_mut() {
+                    code.push_str(&text);
+                }
+            }
+            Event::End(TagEnd::CodeBlock) => {
+                if let Some((mut code, start)) = current.take() {
+                    // Drop the line break immediately before the closing
+                    // fence, so the code ends with its last line.
+                    if code.ends_with('\n') {
+                        code.pop();
+                    }
+                    blocks.push((code, start..range.end));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    blocks
+}
+
+/// Return the text between the start of `content`'s line containing
+/// `pos` and `pos` itself, e.g. the `"> "` or list indentation a fenced
+/// code block sits behind.
+fn line_prefix_before(content: &str, pos: usize) -> String {
+    let line_start = content[..pos].rfind('\n').map(|i| i + 1).unwrap_or(0);
+    content[line_start..pos].to_string()
+}
+
+/// Prefix every line but the first of `text` with `prefix`, so a
+/// multi-line replacement stays inside the blockquote/list item its
+/// source block was nested in.
+fn reindent_with_prefix(text: &str, prefix: &str) -> String {
+    if prefix.is_empty() {
+        return text.to_string();
+    }
+
+    text.split('\n')
+        .enumerate()
+        .map(|(i, line)| if i == 0 { line.to_string() } else { format!("{}{}", prefix, line) })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
 pub struct DiagramsPreprocessor {
     render_mode: RenderMode,
     mmdc_cmd: String,
@@ -143,32 +203,42 @@ mermaid.initialize({ startOnLoad: true });
     }
 
     fn process_book_for_runtime_mode(&self, mut book: Book) -> Result<Book> {
-        let mermaid_re = Regex::new(r#"```mermaid\r?\n([\s\S]*?)\r?\n```"#)?;
-
         for item in &mut book.sections {
-            Self::process_book_item_for_runtime_mode(&mermaid_re, item);
+            Self::process_book_item_for_runtime_mode(item);
         }
 
         Ok(book)
     }
 
     /// Recursively process book items to convert mermaid blocks to HTML `pre` tags
-    fn process_book_item_for_runtime_mode(mermaid_re: &Regex, book_item: &mut BookItem) {
+    fn process_book_item_for_runtime_mode(book_item: &mut BookItem) {
         if let BookItem::Chapter(chapter) = book_item {
-            chapter.content = mermaid_re.replace_all(&chapter.content, |caps: &regex::Captures| {
-                let diagram_code = &caps[1];
-                format!("<pre class=\"mermaid\">\n{}\n</pre>", diagram_code)
-            }).to_string();
+            let mut blocks = find_mermaid_blocks(&chapter.content);
+            blocks.sort_by_key(|(_, range)| range.start);
+
+            // Replace from the end so earlier ranges stay valid as we go.
+            for (diagram_code, range) in blocks.into_iter().rev() {
+                let pre_tag = format!("<pre class=\"mermaid\">\n{}\n</pre>", diagram_code);
+                // The block's own first line keeps whatever blockquote/list
+                // prefix precedes `range.start` in the source; re-apply that
+                // prefix to the lines we're inserting, with list markers
+                // blanked to spaces, so a block nested in a blockquote or
+                // list item doesn't fall out of it.
+                let prefix: String = line_prefix_before(&chapter.content, range.start)
+                    .chars()
+                    .map(|c| if c == '>' || c.is_whitespace() { c } else { ' ' })
+                    .collect();
+                let pre_tag = reindent_with_prefix(&pre_tag, &prefix);
+                chapter.content.replace_range(range, &pre_tag);
+            }
 
             for sub_item in &mut chapter.sub_items {
-                Self::process_book_item_for_runtime_mode(mermaid_re, sub_item);
+                Self::process_book_item_for_runtime_mode(sub_item);
             }
         }
     }
 
     async fn async_process_book(&self, ctx: &PreprocessorContext, book: &mut Book) -> Result<()> {
-        let mermaid_re = Regex::new(r#"```mermaid\r?\n([\s\S]*?)\r?\n```"#)?;
-
         let output_dir = ctx.root.join(&ctx.config.book.src).join("generated").join("diagrams");
         tokio::fs::create_dir_all(&output_dir).await?;
 
@@ -181,7 +251,6 @@ mermaid.initialize({ startOnLoad: true });
         let mut all_futures = Vec::new();
         for item in &mut book.sections {
             all_futures.extend(self.collect_edits_from_book_item_recursively(
-                &mermaid_re,
                 item,
                 &output_dir,
                 &semaphore,
@@ -250,7 +319,6 @@ mermaid.initialize({ startOnLoad: true });
 
     fn collect_edits_from_book_item_recursively(
         &'_ self,
-        mermaid_re: & Regex,
         book_item: & BookItem,
         output_dir: & PathBuf,
         semaphore: & Arc<tokio::sync::Semaphore>,
@@ -259,13 +327,13 @@ mermaid.initialize({ startOnLoad: true });
         if let BookItem::Chapter(chapter) = book_item {
             // Collect edits from a chapter
             futures.extend(
-                self.collect_edits_from_chapter(mermaid_re, chapter, output_dir, semaphore),
+                self.collect_edits_from_chapter(chapter, output_dir, semaphore),
             );
 
             // Proceed recursively for sub items
             for sub_item in &chapter.sub_items {
                 futures.extend(self.collect_edits_from_book_item_recursively(
-                    &mermaid_re, sub_item, &output_dir, &semaphore,
+                    sub_item, &output_dir, &semaphore,
                 ));
             }
         }
@@ -275,17 +343,14 @@ mermaid.initialize({ startOnLoad: true });
     /// Generate diagrams for all mermaid blocks in a chapter and return a list of edits to apply.
     fn collect_edits_from_chapter(
         &'_ self,
-        mermaid_re: & Regex,
         chapter: & Chapter,
         output_dir: & PathBuf,
         semaphore: & Arc<tokio::sync::Semaphore>,
     ) -> Vec<BoxFuture<'_, Result<ChapterEdit>>> {
         let mut futures = Vec::new();
 
-        for cap in mermaid_re.captures_iter(&chapter.content) {
-            let full_match_range = cap.get(0).unwrap().range();
-            let mermaid_code = cap[1].to_string();
-            let original_block = cap.get(0).unwrap().as_str().to_string();
+        for (mermaid_code, full_match_range) in find_mermaid_blocks(&chapter.content) {
+            let original_block = chapter.content[full_match_range.clone()].to_string();
 
             let cache_hash = Self::compute_cache_hash(&mermaid_code, &self.output_format, &self.mmdc_cmd);
             let output_filename = format!("{}.{}", cache_hash, self.output_format);
@@ -461,3 +526,80 @@ impl Preprocessor for DiagramsPreprocessor {
         Ok(book)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_tilde_fenced_mermaid_block() {
+        let content = "~~~mermaid\ngraph TD\n  A --> B\n~~~\n";
+        let blocks = find_mermaid_blocks(content);
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].0, "graph TD\n  A --> B");
+    }
+
+    #[test]
+    fn finds_mermaid_block_with_four_backtick_fence() {
+        let content = "````mermaid\ngraph TD\n  A --> B\n````\n";
+        let blocks = find_mermaid_blocks(content);
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].0, "graph TD\n  A --> B");
+    }
+
+    #[test]
+    fn finds_mermaid_block_with_extra_info_string_attributes() {
+        let content = "```mermaid title=\"flow\"\ngraph TD\n  A --> B\n```\n";
+        let blocks = find_mermaid_blocks(content);
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].0, "graph TD\n  A --> B");
+    }
+
+    #[test]
+    fn ignores_fenced_blocks_with_other_languages() {
+        let content = "```rust\nfn main() {}\n```\n";
+        assert!(find_mermaid_blocks(content).is_empty());
+    }
+
+    #[test]
+    fn finds_mermaid_block_indented_in_a_blockquote() {
+        let content = "> ```mermaid\n> graph TD\n>   A --> B\n> ```\n> more quote\n";
+        let blocks = find_mermaid_blocks(content);
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].0, "graph TD\n  A --> B");
+    }
+
+    #[test]
+    fn finds_mermaid_block_indented_in_a_list_item() {
+        let content = "- item1\n  ```mermaid\n  graph TD\n    A --> B\n  ```\n- item2\n";
+        let blocks = find_mermaid_blocks(content);
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].0, "graph TD\n  A --> B");
+    }
+
+    #[test]
+    fn runtime_mode_preserves_blockquote_prefix_on_every_line() {
+        let content = "> ```mermaid\n> graph TD\n>   A --> B\n> ```\n> more quote".to_string();
+        let mut book_item = BookItem::Chapter(Chapter::new("test", content, "test.md", Vec::new()));
+        DiagramsPreprocessor::process_book_item_for_runtime_mode(&mut book_item);
+
+        let BookItem::Chapter(chapter) = book_item else { panic!("expected a chapter") };
+        assert_eq!(
+            chapter.content,
+            "> <pre class=\"mermaid\">\n> graph TD\n>   A --> B\n> </pre>\n> more quote"
+        );
+    }
+
+    #[test]
+    fn runtime_mode_preserves_list_item_indentation_on_every_line() {
+        let content = "- item1\n  ```mermaid\n  graph TD\n    A --> B\n  ```\n- item2".to_string();
+        let mut book_item = BookItem::Chapter(Chapter::new("test", content, "test.md", Vec::new()));
+        DiagramsPreprocessor::process_book_item_for_runtime_mode(&mut book_item);
+
+        let BookItem::Chapter(chapter) = book_item else { panic!("expected a chapter") };
+        assert_eq!(
+            chapter.content,
+            "- item1\n  <pre class=\"mermaid\">\n  graph TD\n    A --> B\n  </pre>\n- item2"
+        );
+    }
+}